@@ -1,8 +1,17 @@
 //! Dataset iterators.
 use crate::{kind, Device, Kind, Tensor};
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::fs::File;
-use std::io::{BufReader, Read, Result};
+use std::io::{BufReader, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShuffleKind {
+    None,
+    Row,
+    Batch,
+}
 
 /// An iterator over a pair of tensors which have the same first dimension
 /// size.
@@ -18,6 +27,8 @@ pub struct Iter2 {
     total_size: i64,
     device: Device,
     return_smaller_last_batch: bool,
+    batch_perm: Option<Tensor>,
+    shuffle_kind: ShuffleKind,
 }
 
 impl Iter2 {
@@ -45,6 +56,8 @@ impl Iter2 {
             total_size,
             device: Device::Cpu,
             return_smaller_last_batch: false,
+            batch_perm: None,
+            shuffle_kind: ShuffleKind::None,
         }
     }
 
@@ -56,6 +69,50 @@ impl Iter2 {
         let index = Tensor::randperm(self.total_size, kind::INT64_CPU);
         self.xs = self.xs.index_select(0, &index);
         self.ys = self.ys.index_select(0, &index);
+        self.shuffle_kind = ShuffleKind::Row;
+        self
+    }
+
+    /// Like [`Iter2::shuffle`], but first seeds the random number generator
+    /// so that the permutation (and any later [`Iter2::reshuffle`] calls)
+    /// are reproducible across runs.
+    pub fn shuffle_seeded(&mut self, seed: i64) -> &mut Iter2 {
+        crate::manual_seed(seed);
+        self.shuffle()
+    }
+
+    /// Shuffles the dataset at the batch level rather than the row level.
+    ///
+    /// Rows keep their relative order within a batch, but the order in which
+    /// whole batches are returned by the iterator is randomized. This is
+    /// useful when rows are pre-sorted or grouped (e.g. by sequence length),
+    /// so that shuffling individual rows would hurt batching efficiency
+    /// (more padding) while shuffling batches still randomizes presentation
+    /// order across epochs.
+    pub fn batch_shuffle(&mut self) -> &mut Iter2 {
+        let n_batches = (self.total_size + self.batch_size - 1) / self.batch_size;
+        self.batch_perm = Some(Tensor::randperm(n_batches, kind::INT64_CPU));
+        self.shuffle_kind = ShuffleKind::Batch;
+        self
+    }
+
+    /// Regenerates the random permutation used by the last call to
+    /// [`Iter2::shuffle`] or [`Iter2::batch_shuffle`] (a no-op if neither was
+    /// ever called) and resets the iterator back to its first batch.
+    ///
+    /// This lets a multi-epoch training loop see a fresh order every epoch:
+    /// `for _ in 0..n_epochs { for batch in &mut iter { ... } iter.reshuffle(); }`.
+    pub fn reshuffle(&mut self) -> &mut Iter2 {
+        match self.shuffle_kind {
+            ShuffleKind::None => (),
+            ShuffleKind::Row => {
+                self.shuffle();
+            }
+            ShuffleKind::Batch => {
+                self.batch_shuffle();
+            }
+        }
+        self.batch_index = 0;
         self
     }
 
@@ -75,21 +132,142 @@ impl Iter2 {
 impl Iterator for Iter2 {
     type Item = (Tensor, Tensor);
 
+    fn next(&mut self) -> Option<Self::Item> {
+        let n_batches = (self.total_size + self.batch_size - 1) / self.batch_size;
+        while self.batch_index < n_batches {
+            let block_index = match &self.batch_perm {
+                Some(perm) => i64::from(perm.get(self.batch_index)),
+                None => self.batch_index,
+            };
+            self.batch_index += 1;
+            let start = block_index * self.batch_size;
+            let size = std::cmp::min(self.batch_size, self.total_size - start);
+            if size <= 0 {
+                continue;
+            }
+            if !self.return_smaller_last_batch && size < self.batch_size {
+                continue;
+            }
+            return Some((
+                self.xs.narrow(0, start, size).to_device(self.device),
+                self.ys.narrow(0, start, size).to_device(self.device),
+            ));
+        }
+        None
+    }
+}
+
+/// Turns a 1-D tensor of `Int64` labels into a 2-D one-hot float matrix of
+/// shape `(labels.size()[0], num_classes)`, suitable as a target for
+/// cross-entropy losses that expect a probability distribution.
+pub fn one_hot(labels: &Tensor, num_classes: i64) -> Tensor {
+    let shape = [labels.size()[0], num_classes];
+    Tensor::zeros(&shape, kind::FLOAT_CPU).scatter_value_(1, &labels.reshape(&[-1, 1]), 1.0)
+}
+
+/// An iterator over mini-batches drawn from a random subset of a dataset's
+/// indices, rather than the whole dataset.
+///
+/// This is handy for quick validation passes or bootstrap sampling: a
+/// `k`-sized subset of `0..total_size` is drawn once (via a truncated
+/// `randperm`), and batches are served by narrowing into that subset.
+#[derive(Debug)]
+pub struct SubsetIter {
+    indexes: Tensor,
+    subset_size: i64,
+    batch_index: i64,
+    batch_size: i64,
+}
+
+impl SubsetIter {
+    /// Returns a new iterator over a random `subset_size`-sized subset of
+    /// `0..total_size`. `subset_size` is clamped to `total_size` if larger.
+    pub fn new(total_size: i64, subset_size: i64, batch_size: i64) -> SubsetIter {
+        let subset_size = std::cmp::min(subset_size, total_size);
+        let indexes = Tensor::randperm(total_size, kind::INT64_CPU).narrow(0, 0, subset_size);
+        SubsetIter { indexes, subset_size, batch_index: 0, batch_size }
+    }
+}
+
+impl Iterator for SubsetIter {
+    type Item = Tensor;
+
     fn next(&mut self) -> Option<Self::Item> {
         let start = self.batch_index * self.batch_size;
-        let size = std::cmp::min(self.batch_size, self.total_size - start);
-        if size <= 0 || (!self.return_smaller_last_batch && size < self.batch_size) {
+        let size = std::cmp::min(self.batch_size, self.subset_size - start);
+        if size <= 0 {
             None
         } else {
             self.batch_index += 1;
-            Some((
-                self.xs.narrow(0, start, size).to_device(self.device),
-                self.ys.narrow(0, start, size).to_device(self.device),
-            ))
+            Some(self.indexes.narrow(0, start, size))
+        }
+    }
+}
+
+/// An adapter that runs batch production of a wrapped iterator on a
+/// background thread, `to_device`-ing each batch there, and buffering ready
+/// batches in a bounded channel so the next batch is already staged on
+/// `device` while the model is still computing on the current one.
+pub struct Prefetch {
+    receiver: Option<std::sync::mpsc::Receiver<(Tensor, Tensor)>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Prefetch {
+    /// Wraps `iter`, producing batches on a background thread, transferring
+    /// each one to `device`, and buffering up to `capacity` of them ahead of
+    /// the consumer.
+    pub fn new<I>(iter: I, capacity: usize, device: Device) -> Prefetch
+    where
+        I: Iterator<Item = (Tensor, Tensor)> + Send + 'static,
+    {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(capacity);
+        let handle = std::thread::spawn(move || {
+            for (xs, ys) in iter {
+                let batch = (xs.to_device(device), ys.to_device(device));
+                if sender.send(batch).is_err() {
+                    // The consumer (and its receiver) was dropped: stop
+                    // producing batches nobody will read.
+                    break;
+                }
+            }
+        });
+        Prefetch { receiver: Some(receiver), handle: Some(handle) }
+    }
+}
+
+impl Iterator for Prefetch {
+    type Item = (Tensor, Tensor);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        // Drop the receiver first so the worker thread's next `send` fails
+        // and it exits promptly, then join it for a clean shutdown.
+        self.receiver.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
     }
 }
 
+/// Extension trait adding [`Prefetch`] to any batch iterator, e.g.
+/// `iter.prefetch(4, Device::Cpu)`.
+pub trait PrefetchIterator: Iterator<Item = (Tensor, Tensor)> + Sized + Send + 'static {
+    /// Wraps this iterator so that batch production runs on a background
+    /// thread, transferring each batch to `device` and buffering up to
+    /// `capacity` of them ahead of the consumer.
+    fn prefetch(self, capacity: usize, device: Device) -> Prefetch {
+        Prefetch::new(self, capacity, device)
+    }
+}
+
+impl<I: Iterator<Item = (Tensor, Tensor)> + Send + 'static> PrefetchIterator for I {}
+
 /// Text data holder.
 #[derive(Debug)]
 pub struct TextData {
@@ -105,6 +283,7 @@ pub struct TextDataIter {
     batch_size: i64,
     indexes: Tensor,
     indexes_len: i64,
+    contiguous: bool,
 }
 
 impl TextData {
@@ -155,7 +334,79 @@ impl TextData {
             batch_size,
             indexes: Tensor::randperm(indexes_len, kind::INT64_CPU),
             indexes_len,
+            contiguous: false,
+        }
+    }
+
+    /// Like [`TextData::iter_shuffle`], but first seeds the random number
+    /// generator so that the permutation (and any later
+    /// [`TextDataIter::reshuffle`] calls) are reproducible across runs.
+    pub fn iter_shuffle_seeded(&self, seed: i64, seq_len: i64, batch_size: i64) -> TextDataIter {
+        crate::manual_seed(seed);
+        self.iter_shuffle(seq_len, batch_size)
+    }
+
+    /// Returns a batch iterator over the dataset using non-overlapping
+    /// contiguous chunks of seq_len characters, rather than every
+    /// overlapping length-seq_len window as [`TextData::iter_shuffle`] does.
+    /// Each chunk is yielded at most once per epoch; the characters after the
+    /// last whole chunk, and the chunks of a final batch smaller than
+    /// `batch_size`, are not yielded.
+    pub fn iter_contiguous(&self, seq_len: i64, batch_size: i64) -> TextDataIter {
+        let n_chunks = self.data.size()[0] / seq_len;
+        let offsets: Vec<i64> = (0..n_chunks).map(|i| i * seq_len).collect();
+        TextDataIter {
+            data: self.data.shallow_clone(),
+            seq_len,
+            batch_index: 0,
+            batch_size,
+            indexes: Tensor::of_slice(&offsets),
+            indexes_len: n_chunks,
+            contiguous: true,
+        }
+    }
+
+    /// Auto-regressively samples `sample_len` characters.
+    ///
+    /// `logits_fn` maps a seed sequence tensor of shape `(1, seq_len)` (of
+    /// `Int64` labels) to next-character logits of shape `(1, labels())`. At
+    /// each step the logits are turned into a probability distribution via
+    /// softmax and a character is drawn from it with [`Tensor::multinomial`]
+    /// (swap in `.argmax(-1, false)` for greedy decoding instead); the
+    /// sampled character is appended to the result and the window slides
+    /// forward by one.
+    pub fn sample<F>(&self, seq_len: i64, sample_len: i64, logits_fn: F) -> String
+    where
+        F: Fn(&Tensor) -> Tensor,
+    {
+        let mut input = Tensor::zeros(&[1, seq_len], kind::INT64_CPU);
+        let mut result = String::new();
+        for _ in 0..sample_len {
+            let logits = logits_fn(&input);
+            let sampled = logits.softmax(-1, Kind::Float).multinomial(1, true);
+            result.push(self.label_to_char(sampled.int64_value(&[0, 0])));
+            input = Tensor::cat(&[input.narrow(1, 1, seq_len - 1), sampled.view([1, 1])], 1);
         }
+        result
+    }
+}
+
+impl TextDataIter {
+    /// Regenerates the permutation used to order samples and resets the
+    /// iterator back to its first batch, so that a
+    /// `for _ in 0..n_epochs { for batch in &mut iter { ... } iter.reshuffle(); }`
+    /// loop sees a fresh order every epoch instead of repeating the first
+    /// one. For a [`TextData::iter_contiguous`] iterator, which is already
+    /// in a fixed non-overlapping order, this just restarts the iterator.
+    pub fn reshuffle(&mut self) -> &mut TextDataIter {
+        self.indexes = if self.contiguous {
+            let offsets: Vec<i64> = (0..self.indexes_len).map(|i| i * self.seq_len).collect();
+            Tensor::of_slice(&offsets)
+        } else {
+            Tensor::randperm(self.indexes_len, kind::INT64_CPU)
+        };
+        self.batch_index = 0;
+        self
     }
 }
 
@@ -179,3 +430,462 @@ impl Iterator for TextDataIter {
         }
     }
 }
+
+/// Default number of (key, row) pairs buffered in memory before a shard is
+/// sorted and flushed to disk.
+const SHARD_BUFFER_LEN: usize = 4096;
+
+/// Number of (key, row) pairs per on-disk page within a shard. A shard is
+/// split into pages of this size so that reading it back only ever needs one
+/// page in memory at a time, rather than the whole (potentially large) shard.
+const SHARD_PAGE_LEN: usize = 256;
+
+/// Byte range of one page within a shard file: `(offset, length)`.
+type PageRange = (u64, u64);
+
+/// Metadata describing one on-disk shard, as recorded in the index file:
+/// its file path, the key range covered by its rows, and the byte range of
+/// each page within that one file, in sorted-key order.
+#[derive(Debug, Clone)]
+struct ShardMeta {
+    path: PathBuf,
+    min_key: i64,
+    max_key: i64,
+    pages: Vec<PageRange>,
+}
+
+/// Writer side of an out-of-core, key-sorted dataset.
+///
+/// Rows are pushed in arbitrary order together with a sort key (e.g. a
+/// sequence length used to minimize padding within a batch). Internally rows
+/// are buffered, sorted by key, and flushed to disk as a single shard file
+/// (itself split into small pages, each recorded as a byte range) once the
+/// buffer is full. [`ShardedDataset::open`] later streams shards back out in
+/// globally sorted key order without requiring the whole dataset in memory.
+#[derive(Debug)]
+pub struct ShardWriter {
+    base_path: PathBuf,
+    buffer: Vec<(i64, Tensor)>,
+    shards: Vec<ShardMeta>,
+}
+
+impl ShardWriter {
+    /// Creates a new shard writer. Shard files are written next to `path`,
+    /// and an index file recording their key ranges and page byte ranges is
+    /// written to `path` once [`ShardWriter::finish`] is called.
+    pub fn new<P: AsRef<Path>>(path: P) -> ShardWriter {
+        ShardWriter { base_path: path.as_ref().to_path_buf(), buffer: vec![], shards: vec![] }
+    }
+
+    /// Buffers a single (key, row) pair, flushing a shard to disk once the
+    /// buffer reaches [`SHARD_BUFFER_LEN`] items.
+    pub fn push(&mut self, key: i64, row: &Tensor) -> Result<()> {
+        self.buffer.push((key, row.shallow_clone()));
+        if self.buffer.len() >= SHARD_BUFFER_LEN {
+            self.flush_shard()?;
+        }
+        Ok(())
+    }
+
+    fn flush_shard(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.sort_by_key(|(key, _)| *key);
+        let min_key = self.buffer.first().unwrap().0;
+        let max_key = self.buffer.last().unwrap().0;
+        let shard_path = self.base_path.with_extension(format!("shard-{}", self.shards.len()));
+        let mut shard_file = File::create(&shard_path)?;
+        let mut pages = Vec::new();
+        for page in self.buffer.chunks(SHARD_PAGE_LEN) {
+            let keys: Vec<i64> = page.iter().map(|(key, _)| *key).collect();
+            let rows: Vec<&Tensor> = page.iter().map(|(_, row)| row).collect();
+            let keys = Tensor::of_slice(&keys);
+            let rows = Tensor::stack(&rows, 0);
+            pages.push(append_page(&mut shard_file, &keys, &rows)?);
+        }
+        self.shards.push(ShardMeta { path: shard_path, min_key, max_key, pages });
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Flushes any buffered rows and writes the shard index as a separate
+    /// text file at the path given to [`ShardWriter::new`]; no index is
+    /// appended to the shard files themselves.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush_shard()?;
+        let mut index = String::new();
+        index.push_str(&self.shards.len().to_string());
+        index.push('\n');
+        for shard in &self.shards {
+            index.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                shard.path.display(),
+                shard.min_key,
+                shard.max_key,
+                shard.pages.len()
+            ));
+            for (offset, length) in &shard.pages {
+                index.push_str(&format!("{}\t{}\n", offset, length));
+            }
+        }
+        std::fs::write(&self.base_path, index)
+    }
+}
+
+/// Counter used to give temporary per-page scratch files a unique name. Real
+/// randomness/timestamps aren't needed, just uniqueness within the process.
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn tmp_path() -> PathBuf {
+    let id = TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("tch-shard-page-{}-{}.tmp", std::process::id(), id))
+}
+
+/// Serializes `(keys, rows)` and appends them to `shard_file`, returning the
+/// byte range they occupy so the page can later be read back with a single
+/// seek, without needing the rest of the shard file in memory.
+///
+/// `Tensor::save_multi` only writes to a path, not an arbitrary writer, so
+/// the page is first serialized to a scratch file and its bytes copied into
+/// the shard file at the current end-of-file offset.
+fn append_page(shard_file: &mut File, keys: &Tensor, rows: &Tensor) -> Result<PageRange> {
+    let scratch = tmp_path();
+    save_tensors(&scratch, keys, rows)?;
+    let bytes = std::fs::read(&scratch)?;
+    std::fs::remove_file(&scratch)?;
+    let offset = shard_file.seek(SeekFrom::End(0))?;
+    shard_file.write_all(&bytes)?;
+    Ok((offset, bytes.len() as u64))
+}
+
+/// Reads back the page written by [`append_page`] at `(offset, length)`
+/// within `shard_path`, without loading the rest of the shard file.
+fn read_page(shard_path: &Path, (offset, length): PageRange) -> Result<(Tensor, Tensor)> {
+    let mut file = File::open(shard_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![0u8; length as usize];
+    file.read_exact(&mut bytes)?;
+    let scratch = tmp_path();
+    std::fs::write(&scratch, &bytes)?;
+    let result = load_tensors(&scratch);
+    std::fs::remove_file(&scratch)?;
+    result
+}
+
+fn save_tensors(path: &Path, keys: &Tensor, rows: &Tensor) -> Result<()> {
+    Tensor::save_multi(&[("keys", keys), ("rows", rows)], path).map_err(other_error)
+}
+
+fn load_tensors(path: &Path) -> Result<(Tensor, Tensor)> {
+    let named = Tensor::load_multi(path).map_err(other_error)?;
+    let keys = named.iter().find(|(name, _)| name == "keys").unwrap().1.shallow_clone();
+    let rows = named.iter().find(|(name, _)| name == "rows").unwrap().1.shallow_clone();
+    Ok((keys, rows))
+}
+
+fn other_error<E: std::fmt::Display>(err: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+}
+
+/// An out-of-core dataset streamed from the shards written by a
+/// [`ShardWriter`], for data that does not fit in memory.
+#[derive(Debug)]
+pub struct ShardedDataset {
+    shards: Vec<ShardMeta>,
+}
+
+impl ShardedDataset {
+    /// Opens a sharded dataset previously written via [`ShardWriter`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ShardedDataset> {
+        let index = std::fs::read_to_string(path.as_ref())?;
+        let mut lines = index.lines();
+        let shard_count: usize = lines
+            .next()
+            .ok_or_else(|| other_error("empty shard index"))?
+            .parse()
+            .map_err(other_error)?;
+        let mut shards = Vec::with_capacity(shard_count);
+        for _ in 0..shard_count {
+            let mut fields = lines
+                .next()
+                .ok_or_else(|| other_error("malformed shard index"))?
+                .split('\t');
+            let path = fields.next().ok_or_else(|| other_error("malformed shard index"))?;
+            let min_key: i64 = fields
+                .next()
+                .ok_or_else(|| other_error("malformed shard index"))?
+                .parse()
+                .map_err(other_error)?;
+            let max_key: i64 = fields
+                .next()
+                .ok_or_else(|| other_error("malformed shard index"))?
+                .parse()
+                .map_err(other_error)?;
+            let page_count: usize = fields
+                .next()
+                .ok_or_else(|| other_error("malformed shard index"))?
+                .parse()
+                .map_err(other_error)?;
+            let mut pages = Vec::with_capacity(page_count);
+            for _ in 0..page_count {
+                let mut page_fields = lines
+                    .next()
+                    .ok_or_else(|| other_error("malformed shard index"))?
+                    .split('\t');
+                let offset: u64 = page_fields
+                    .next()
+                    .ok_or_else(|| other_error("malformed shard index"))?
+                    .parse()
+                    .map_err(other_error)?;
+                let length: u64 = page_fields
+                    .next()
+                    .ok_or_else(|| other_error("malformed shard index"))?
+                    .parse()
+                    .map_err(other_error)?;
+                pages.push((offset, length));
+            }
+            shards.push(ShardMeta { path: PathBuf::from(path), min_key, max_key, pages });
+        }
+        Ok(ShardedDataset { shards })
+    }
+
+    /// Returns an iterator over `(keys, rows)` mini-batches, merging shards so
+    /// that items come out in globally sorted key order while only keeping
+    /// one page per shard in memory at a time, refilling from disk as each
+    /// page is drained.
+    pub fn iter_batches(&self, batch_size: i64, device: Device) -> Result<ShardedDatasetIter> {
+        self.iter_batches_in_range(batch_size, device, i64::MIN, i64::MAX)
+    }
+
+    /// Like [`ShardedDataset::iter_batches`], but skips shards whose key
+    /// range (recorded in the index at write time) doesn't overlap
+    /// `[min_key, max_key]` at all, without opening their file.
+    pub fn iter_batches_in_range(
+        &self,
+        batch_size: i64,
+        device: Device,
+        min_key: i64,
+        max_key: i64,
+    ) -> Result<ShardedDatasetIter> {
+        let mut cursors = Vec::with_capacity(self.shards.len());
+        let mut heap = BinaryHeap::new();
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let cursor = if shard.max_key < min_key || shard.min_key > max_key {
+                None
+            } else {
+                ShardCursor::new(shard.path.clone(), shard.pages.iter().cloned().collect())?
+            };
+            if let Some(cursor) = &cursor {
+                heap.push(std::cmp::Reverse((cursor.current_key(), shard_index)));
+            }
+            cursors.push(cursor);
+        }
+        Ok(ShardedDatasetIter { cursors, heap, batch_size, device })
+    }
+}
+
+/// Tracks the one page currently held in memory for a single shard, loading
+/// the next page from disk once the current one is drained.
+struct ShardCursor {
+    path: PathBuf,
+    pages: VecDeque<PageRange>,
+    keys: Tensor,
+    rows: Tensor,
+    row_index: i64,
+    len: i64,
+}
+
+impl ShardCursor {
+    fn new(path: PathBuf, mut pages: VecDeque<PageRange>) -> Result<Option<ShardCursor>> {
+        match pages.pop_front() {
+            None => Ok(None),
+            Some(page) => {
+                let (keys, rows) = read_page(&path, page)?;
+                let len = keys.size()[0];
+                Ok(Some(ShardCursor { path, pages, keys, rows, row_index: 0, len }))
+            }
+        }
+    }
+
+    fn current_key(&self) -> i64 {
+        self.keys.int64_value(&[self.row_index])
+    }
+
+    fn current_row(&self) -> Tensor {
+        self.rows.get(self.row_index)
+    }
+
+    /// Advances to the next row, loading the next page from disk if the
+    /// current one is exhausted. Returns `false` once the shard is done.
+    fn advance(&mut self) -> Result<bool> {
+        self.row_index += 1;
+        if self.row_index < self.len {
+            return Ok(true);
+        }
+        match self.pages.pop_front() {
+            None => Ok(false),
+            Some(page) => {
+                let (keys, rows) = read_page(&self.path, page)?;
+                self.len = keys.size()[0];
+                self.keys = keys;
+                self.rows = rows;
+                self.row_index = 0;
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Iterator returned by [`ShardedDataset::iter_batches`].
+///
+/// Yields `Result`s rather than panicking so that a mid-iteration I/O error
+/// on a shard page can be surfaced to (and handled by) the caller.
+pub struct ShardedDatasetIter {
+    cursors: Vec<Option<ShardCursor>>,
+    heap: BinaryHeap<std::cmp::Reverse<(i64, usize)>>,
+    batch_size: i64,
+    device: Device,
+}
+
+impl Iterator for ShardedDatasetIter {
+    type Item = Result<(Tensor, Tensor)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.heap.is_empty() {
+            return None;
+        }
+        let mut keys = Vec::with_capacity(self.batch_size as usize);
+        let mut rows = Vec::with_capacity(self.batch_size as usize);
+        while rows.len() < self.batch_size as usize {
+            let std::cmp::Reverse((key, shard_index)) = match self.heap.pop() {
+                Some(item) => item,
+                None => break,
+            };
+            let cursor = self.cursors[shard_index].as_mut().unwrap();
+            keys.push(key);
+            rows.push(cursor.current_row());
+            match cursor.advance() {
+                Ok(true) => {
+                    let next_key = cursor.current_key();
+                    self.heap.push(std::cmp::Reverse((next_key, shard_index)));
+                }
+                Ok(false) => {
+                    self.cursors[shard_index] = None;
+                }
+                Err(err) => {
+                    // Drop the shard so we don't retry a page that just
+                    // failed to read, and surface the error to the caller
+                    // instead of panicking mid-iteration.
+                    self.cursors[shard_index] = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+        let keys = Tensor::of_slice(&keys).to_device(self.device);
+        let rows: Vec<&Tensor> = rows.iter().collect();
+        let rows = Tensor::stack(&rows, 0).to_device(self.device);
+        Some(Ok((keys, rows)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_hot_encodes_labels() {
+        let labels = Tensor::of_slice(&[0i64, 2, 1]);
+        let got = one_hot(&labels, 3);
+        assert_eq!(got.size(), vec![3, 3]);
+        let expected =
+            Tensor::of_slice(&[1f32, 0., 0., 0., 0., 1., 0., 1., 0.]).view([3, 3]);
+        assert_eq!(f64::from((got - expected).abs().sum(Kind::Float)), 0.0);
+    }
+
+    #[test]
+    fn reshuffle_resets_batch_index_for_a_new_epoch() {
+        let xs = Tensor::of_slice(&(0..6i64).collect::<Vec<_>>());
+        let ys = xs.shallow_clone();
+        let mut iter = Iter2::new(&xs, &ys, 2);
+        iter.shuffle();
+        let first_epoch: Vec<i64> = (&mut iter).flat_map(|(xs, _)| Vec::<i64>::from(&xs)).collect();
+        assert_eq!(first_epoch.len(), 6);
+        // The iterator is exhausted; without reshuffle it would keep
+        // returning None forever.
+        assert!(iter.next().is_none());
+
+        iter.reshuffle();
+        let second_epoch: Vec<i64> = (&mut iter).flat_map(|(xs, _)| Vec::<i64>::from(&xs)).collect();
+        let mut sorted_second_epoch = second_epoch.clone();
+        sorted_second_epoch.sort();
+        assert_eq!(sorted_second_epoch, (0..6i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn batch_shuffle_still_covers_every_row_once() {
+        // total_size isn't a multiple of batch_size, so the shuffled batch
+        // order can place the smaller last block anywhere; every row must
+        // still come out exactly once when return_smaller_last_batch is set.
+        let xs = Tensor::of_slice(&(0..7i64).collect::<Vec<_>>());
+        let ys = xs.shallow_clone();
+        let mut iter = Iter2::new(&xs, &ys, 3);
+        iter.batch_shuffle().return_smaller_last_batch();
+        let mut seen: Vec<i64> = vec![];
+        for (batch_xs, _) in &mut iter {
+            seen.extend(Vec::<i64>::from(&batch_xs));
+        }
+        seen.sort();
+        assert_eq!(seen, (0..7i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn subset_iter_clamps_oversized_subset() {
+        let mut iter = SubsetIter::new(4, 10, 2);
+        let mut total = 0;
+        for batch in &mut iter {
+            total += batch.size()[0];
+        }
+        assert_eq!(total, 4);
+    }
+
+    #[test]
+    fn sharded_dataset_round_trips_in_sorted_key_order() {
+        let dir = std::env::temp_dir().join("tch_sharded_dataset_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let index_path = dir.join("dataset.idx");
+
+        // Use more rows than SHARD_PAGE_LEN so the round-trip exercises
+        // multiple pages within a shard, not just a single in-memory buffer.
+        let n_rows = SHARD_PAGE_LEN as i64 * 3;
+        let mut writer = ShardWriter::new(&index_path);
+        for key in (0..n_rows).rev() {
+            writer.push(key, &Tensor::of_slice(&[key])).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let dataset = ShardedDataset::open(&index_path).unwrap();
+        let mut seen = Vec::new();
+        for batch in dataset.iter_batches(16, Device::Cpu).unwrap() {
+            let (keys, _rows) = batch.unwrap();
+            seen.extend(Vec::<i64>::from(&keys));
+        }
+        let expected: Vec<i64> = (0..n_rows).collect();
+        assert_eq!(seen, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prefetch_forwards_all_batches() {
+        let xs = Tensor::of_slice(&(0..10i64).collect::<Vec<_>>());
+        let ys = Tensor::of_slice(&(0..10i64).collect::<Vec<_>>());
+        let mut iter = Iter2::new(&xs, &ys, 2).prefetch(2, Device::Cpu);
+        let mut n_batches = 0;
+        for (batch_xs, _) in &mut iter {
+            assert_eq!(batch_xs.size(), vec![2]);
+            n_batches += 1;
+        }
+        assert_eq!(n_batches, 5);
+    }
+}